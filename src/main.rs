@@ -1,102 +1,382 @@
-use std::f32::consts::PI;
+use std::{
+    collections::HashSet, f32::consts::PI, fs::File, io::BufReader, net::SocketAddr,
+    time::Duration,
+};
 
 use bevy::{
-    core::FixedTimestep,
     prelude::*,
     render::pass::ClearColor,
     ecs::system::EntityCommands,
 };
-use bevy_rapier2d::{prelude::*, na};
+use bevy_rapier2d::{prelude::*, na, physics::PhysicsStages};
+use bevy_ggrs::{GGRSPlugin, Rollback, RollbackIdProvider, SessionType};
+use ggrs::{Config, PlayerHandle, PlayerType, SessionBuilder, UdpNonBlockingSocket};
+use bytemuck::{Pod, Zeroable};
+use serde::Deserialize;
+use structopt::StructOpt;
 
 const FPS: f32 = 60.0;
-const TIME_STEP: f32 = 1.0 / FPS;
+
+const MAX_PREDICTION_WINDOW: usize = 12;
+const INPUT_DELAY: usize = 2;
+
+const INPUT_UP: u8 = 1 << 0;
+const INPUT_DOWN: u8 = 1 << 1;
+const INPUT_LEFT: u8 = 1 << 2;
+const INPUT_RIGHT: u8 = 1 << 3;
+const INPUT_FIRE: u8 = 1 << 4;
+
+const BULLET_SPEED: f32 = 500.0;
+const BULLET_LIFETIME_SECS: f32 = 2.0;
+const BULLET_DAMAGE: f32 = 25.0;
+
+/// Command line arguments used to set up the peer-to-peer session. Each
+/// instance needs its own local port and a list of addresses (one per
+/// player, in seat order) with `localhost` marking the local player.
+#[derive(StructOpt)]
+struct Opt {
+    #[structopt(long)]
+    local_port: u16,
+    #[structopt(long)]
+    players: Vec<String>,
+    /// Path to a level file (see `Level`). Falls back to the built-in
+    /// default layout when not given.
+    #[structopt(long)]
+    level: Option<String>,
+}
+
+/// A serializable arena layout: the walls to spawn and the point the
+/// players are spawned around. Lets players author maps without
+/// recompiling.
+#[derive(Deserialize)]
+struct Level {
+    walls: Vec<WallDesc>,
+    spawn: (f32, f32),
+}
+
+/// One wall's center `(x, y)`, `size`, and `rotation` in radians.
+#[derive(Deserialize)]
+struct WallDesc {
+    x: f32,
+    y: f32,
+    size: (f32, f32),
+    rotation: f32,
+}
+
+/// The layout that used to be hard-coded in `setup`, kept as the default
+/// so behavior is unchanged when no `--level` file is given.
+fn default_level() -> Level {
+    let bounds = (800.0, 600.0);
+    let wall_thickness = 10.0;
+
+    Level {
+        walls: vec![
+            // Left Wall
+            WallDesc { x: -bounds.0 / 2.0, y: 0.0, size: (wall_thickness, bounds.1), rotation: 0.0 },
+            // Right Wall
+            WallDesc { x: bounds.0 / 2.0, y: 0.0, size: (wall_thickness, bounds.1), rotation: 0.0 },
+            // Top Wall
+            WallDesc { x: 0.0, y: -bounds.1 / 2.0, size: (bounds.0, wall_thickness), rotation: 0.0 },
+            // Bottom Wall
+            WallDesc { x: 0.0, y: bounds.1 / 2.0, size: (bounds.0, wall_thickness), rotation: 0.0 },
+            // Additional Walls
+            WallDesc {
+                x: -bounds.0 / 2.0 + 200.0,
+                y: bounds.1 / 2.0 - 300.0,
+                size: (bounds.0 / 4.0, wall_thickness * 4.0),
+                rotation: PI / 3.0,
+            },
+            WallDesc {
+                x: bounds.0 / 2.0 - 200.0,
+                y: bounds.1 / 2.0 - 300.0,
+                size: (bounds.0 / 2.0, wall_thickness * 2.0),
+                rotation: -PI / 2.5,
+            },
+        ],
+        spawn: (0.0, 0.0),
+    }
+}
+
+/// Loads a `Level` from `path`, or falls back to [`default_level`] when
+/// no path was given.
+fn load_level(path: Option<&str>) -> Level {
+    let path = match path {
+        Some(path) => path,
+        None => return default_level(),
+    };
+
+    let file = File::open(path).expect("failed to open level file");
+    serde_json::from_reader(BufReader::new(file)).expect("failed to parse level file")
+}
+
+/// Config used to parameterize the GGRS session. `BoxInput` is the only
+/// input we ever need to roll back, and we don't use GGRS's checksum
+/// state tracking, so `State` is left as a placeholder byte.
+#[derive(Debug)]
+struct GGRSConfig;
+
+impl Config for GGRSConfig {
+    type Input = BoxInput;
+    type State = u8;
+    type Address = SocketAddr;
+}
+
+/// The arrow keys and the fire key (Space) packed into a single byte so
+/// GGRS can send and rewind it cheaply. This is the only thing that crosses the network.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Pod, Zeroable)]
+struct BoxInput {
+    inp: u8,
+}
+
+/// Tags a rollback-tracked player entity with its GGRS player handle so
+/// the rollback movement system can look up its decoded input. `prev_inp`
+/// is last frame's decoded input, kept around so firing can be triggered
+/// on the press edge rather than spawning a bullet every frame it's held.
+#[derive(Reflect, Default, Clone)]
+struct Player {
+    handle: PlayerHandle,
+    prev_inp: u8,
+}
+
+/// The direction a player is currently facing, updated whenever they
+/// have non-zero movement input. New bullets launch along this vector.
+#[derive(Reflect, Default, Clone)]
+struct Facing(Vec2);
+
+/// A player-fired projectile. Despawns on collision or once its `Fuse`
+/// timer runs out, whichever comes first.
+struct Bullet;
+
+/// Counts down a fixed lifetime for an entity; when it finishes, the
+/// entity is despawned. Registered as a GGRS rollback type because
+/// `tick_fuses` mutates it from inside the rollback schedule.
+#[derive(Reflect, Default, Clone)]
+struct Fuse(Timer);
+
+/// Hit points. An entity is despawned once `current` reaches zero.
+/// Registered as a GGRS rollback type because `collision_event_system`
+/// mutates it from inside the rollback schedule.
+#[derive(Reflect, Default, Clone)]
+struct Health {
+    current: f32,
+    max: f32,
+}
+
+/// How much `Health` an entity subtracts from whatever it collides with,
+/// e.g. a bullet.
+struct Damage(f32);
+
+/// Marks the player entity controlled by this instance, i.e. one of
+/// `P2PSession::local_player_handles()`. The camera follows this entity.
+struct LocalPlayer;
+
+/// Marks the 2D gameplay camera specifically, so `camera_follow` doesn't
+/// also end up moving the `UiCameraBundle` camera (which carries the
+/// same generic `Camera` component).
+struct MainCamera;
+
+/// How quickly the camera eases toward the local player's position, as
+/// the fraction of the remaining distance closed per second (capped at
+/// the whole distance in one frame). Higher is snappier; lower is
+/// smoother.
+struct CameraFollowSpeed(f32);
 
 fn main() {
-    App::build()
-        .add_plugins(DefaultPlugins)
-        .add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
+    let opt = Opt::from_args();
+    assert!(!opt.players.is_empty());
+
+    let level = load_level(opt.level.as_deref());
+    let num_players = opt.players.len();
+    let mut sess_build = SessionBuilder::<GGRSConfig>::new()
+        .with_num_players(num_players)
+        .with_max_prediction_window(MAX_PREDICTION_WINDOW)
+        .with_input_delay(INPUT_DELAY);
+
+    for (handle, player_addr) in opt.players.iter().enumerate() {
+        if player_addr == "localhost" {
+            sess_build = sess_build
+                .add_player(PlayerType::Local, handle)
+                .expect("failed to add local player");
+        } else {
+            let remote_addr: SocketAddr = player_addr
+                .parse()
+                .expect("invalid player address");
+            sess_build = sess_build
+                .add_player(PlayerType::Remote(remote_addr), handle)
+                .expect("failed to add remote player");
+        }
+    }
+
+    let socket = UdpNonBlockingSocket::bind_to_port(opt.local_port)
+        .expect("failed to bind local UDP socket");
+    let sess = sess_build
+        .start_p2p_session(socket)
+        .expect("failed to start GGRS session");
+
+    let mut app = App::build();
+
+    // The rollback schedule re-runs every time GGRS resimulates a
+    // predicted frame, so everything that can change simulation state —
+    // the physics step itself (not `RapierPhysicsPlugin`'s normal
+    // once-per-real-frame systems), movement, firing, fuse timers, and
+    // collision/damage handling — has to live inside it, and every
+    // component any of those mutate has to be registered as a rollback
+    // type. Otherwise two peers that mispredict by different amounts
+    // advance, or roll back, that state a different number of times and
+    // desync.
+    GGRSPlugin::<GGRSConfig>::new()
+        .with_update_frequency(FPS as usize)
+        .with_input_system(input.system())
+        .register_rollback_type::<Transform>()
+        .register_rollback_type::<RigidBodyPositionComponent>()
+        .register_rollback_type::<RigidBodyVelocityComponent>()
+        .register_rollback_type::<Health>()
+        .register_rollback_type::<Fuse>()
+        .register_rollback_type::<Player>()
+        .register_rollback_type::<Facing>()
+        .with_rollback_schedule(
+            Schedule::default()
+                .with_stage(
+                    PhysicsStages::SyncBackend,
+                    SystemStage::parallel().with_system_set(
+                        RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsStages::SyncBackend),
+                    ),
+                )
+                .with_stage_after(
+                    PhysicsStages::SyncBackend,
+                    PhysicsStages::StepWorld,
+                    SystemStage::parallel().with_system_set(
+                        RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsStages::StepWorld),
+                    ),
+                )
+                .with_stage_after(
+                    PhysicsStages::StepWorld,
+                    PhysicsStages::Writeback,
+                    SystemStage::parallel().with_system_set(
+                        RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsStages::Writeback),
+                    ),
+                )
+                .with_stage_after(
+                    PhysicsStages::Writeback,
+                    "rollback_stage",
+                    SystemStage::single_threaded()
+                        .with_system(rollback_move_players.system())
+                        .with_system(rollback_fire_bullets.system())
+                        .with_system(tick_fuses.system())
+                        .with_system(collision_event_system.system()),
+                ),
+        )
+        .build(&mut app);
+
+    app.add_plugins(DefaultPlugins)
+        .add_plugin(
+            // `with_default_system_setup(false)` stops the plugin from
+            // also scheduling its systems into the normal per-real-frame
+            // stages; we schedule the sync, step and writeback systems
+            // ourselves above, once per rollback-schedule frame, and the
+            // despawn detection below, once per real frame.
+            RapierPhysicsPlugin::<NoUserData>::default().with_default_system_setup(false),
+        )
         .insert_resource(ClearColor(Color::rgb(0.6, 0.6, 0.6)))
+        .insert_resource(num_players)
+        .insert_resource(level)
+        .insert_resource(CameraFollowSpeed(4.0))
+        .insert_resource(sess)
+        .insert_resource(SessionType::P2PSession)
         .add_startup_system(setup.system())
-        .add_system_set(
-            SystemSet::new()
-                .with_run_criteria(FixedTimestep::step(TIME_STEP as f64))
-                .with_system(keyboard_control.system())
+        .add_stage_before(
+            CoreStage::Update,
+            PhysicsStages::DetectDespawn,
+            SystemStage::parallel().with_system_set(
+                RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsStages::DetectDespawn),
+            ),
         )
+        .add_system_to_stage(CoreStage::PostUpdate, camera_follow.system())
         .add_system(bevy::input::system::exit_on_esc_system.system())
         .run();
 }
 
-struct KeyboardControlled;
+/// Decodes the local player's arrow-key and fire-key state into a
+/// `BoxInput`. This is
+/// the only place wall-clock input is read; everything downstream of it
+/// runs inside the GGRS advance-frame stage so it stays deterministic.
+fn input(handle: In<PlayerHandle>, keyboard: Res<Input<KeyCode>>) -> BoxInput {
+    let _ = handle;
+
+    let mut inp: u8 = 0;
+    if keyboard.pressed(KeyCode::Up) {
+        inp |= INPUT_UP;
+    }
+    if keyboard.pressed(KeyCode::Down) {
+        inp |= INPUT_DOWN;
+    }
+    if keyboard.pressed(KeyCode::Left) {
+        inp |= INPUT_LEFT;
+    }
+    if keyboard.pressed(KeyCode::Right) {
+        inp |= INPUT_RIGHT;
+    }
+    if keyboard.pressed(KeyCode::Space) {
+        inp |= INPUT_FIRE;
+    }
+
+    BoxInput { inp }
+}
 
 fn setup(
     mut commands: Commands,
     mut physics_config: ResMut<RapierConfiguration>,
     mut materials: ResMut<Assets<ColorMaterial>>,
+    mut rollback_ids: ResMut<RollbackIdProvider>,
+    num_players: Res<usize>,
+    level: Res<Level>,
+    sess: Res<ggrs::P2PSession<GGRSConfig>>,
     _asset_server: Res<AssetServer>,
 ) {
+    let local_handles = sess.local_player_handles();
+
     // Top-down game, so no gravity
     physics_config.gravity.x = 0.0;
     physics_config.gravity.y = 0.0;
 
     // Cameras
-    commands.spawn_bundle(OrthographicCameraBundle::new_2d());
+    commands.spawn_bundle(OrthographicCameraBundle::new_2d()).insert(MainCamera);
     commands.spawn_bundle(UiCameraBundle::default());
 
     // Walls
     let wall_material = materials.add(Color::rgb(0.5, 0.5, 1.0).into());
-    let wall_thickness = 10.0;
-    let bounds = Vec2::new(800.0, 600.0);
-
-    // Left Wall
-    spawn_rectangle(
-        commands.spawn(),
-        wall_material.clone(),
-        transform2d(-bounds.x / 2.0, 0.0, 0.0, 0.0),
-        Vec2::new(wall_thickness, bounds.y),
-    );
-    // Right Wall
-    spawn_rectangle(
-        commands.spawn(),
-        wall_material.clone(),
-        transform2d(bounds.x / 2.0, 0.0, 0.0, 0.0),
-        Vec2::new(wall_thickness, bounds.y),
-    );
-    // Top Wall
-    spawn_rectangle(
-        commands.spawn(),
-        wall_material.clone(),
-        transform2d(0.0, -bounds.y / 2.0, 0.0, 0.0),
-        Vec2::new(bounds.x, wall_thickness),
-    );
-    // Bottom Wall
-    spawn_rectangle(
-        commands.spawn(),
-        wall_material.clone(),
-        transform2d(0.0, bounds.y / 2.0, 0.0, 0.0),
-        Vec2::new(bounds.x, wall_thickness),
-    );
+    for wall in &level.walls {
+        spawn_rectangle(
+            commands.spawn(),
+            wall_material.clone(),
+            transform2d(wall.x, wall.y, 0.0, wall.rotation),
+            Vec2::new(wall.size.0, wall.size.1),
+        );
+    }
 
-    // Additional Walls
-    spawn_rectangle(
-        commands.spawn(),
-        wall_material.clone(),
-        transform2d(-bounds.x / 2.0 + 200.0, bounds.y / 2.0 - 300.0, 0.0, PI / 3.0),
-        Vec2::new(bounds.x / 4.0, wall_thickness * 4.0),
-    );
-    spawn_rectangle(
-        commands.spawn(),
-        wall_material.clone(),
-        transform2d(bounds.x / 2.0 - 200.0, bounds.y / 2.0 - 300.0, 0.0, -PI / 2.5),
-        Vec2::new(bounds.x / 2.0, wall_thickness * 2.0),
-    );
+    // Players, one per GGRS handle, fanned out around the level's spawn
+    // point so they don't spawn on top of each other.
+    let player_material = materials.add(Color::rgb(1.0, 0.5, 0.5).into());
+    for handle in 0..*num_players {
+        let x = level.spawn.0 + handle as f32 * 60.0 - (*num_players as f32 - 1.0) * 30.0;
+        let y = level.spawn.1;
+        let mut entity = spawn_rigid_body_rectangle(
+            commands.spawn(),
+            player_material.clone(),
+            transform2d(x, y, 1.0, 0.0),
+            Vec2::new(30.0, 30.0),
+        );
+        entity
+            .insert(Player { handle, prev_inp: 0 })
+            .insert(Facing(Vec2::new(0.0, 1.0)))
+            .insert(Health { current: 100.0, max: 100.0 })
+            .insert(Rollback::new(rollback_ids.next_id()));
 
-    // Player
-    spawn_rigid_body_rectangle(
-        commands.spawn(),
-        materials.add(Color::rgb(1.0, 0.5, 0.5).into()),
-        transform2d(0.0, 0.0, 1.0, 0.0),
-        Vec2::new(30.0, 30.0),
-    ).insert(KeyboardControlled);
+        if local_handles.contains(&handle) {
+            entity.insert(LocalPlayer);
+        }
+    }
 }
 
 fn transform2d(x: f32, y: f32, z: f32, radians: f32) -> Transform {
@@ -140,34 +420,193 @@ fn spawn_rectangle<'a, 'b>(
             transform.rotation.to_axis_angle().1,
         )
         .into(),
+        flags: ColliderFlags {
+            active_events: ActiveEvents::COLLISION_EVENTS,
+            ..Default::default()
+        }
+        .into(),
         ..Default::default()
     }).insert(ColliderPositionSync::Discrete);
     entity
 }
 
-fn keyboard_control(
-    keyboard_input: Res<Input<KeyCode>>,
-    mut query: Query<(&KeyboardControlled, &mut RigidBodyVelocity)>,
+/// Spawns a small rigid-body projectile launched along `direction` at
+/// `BULLET_SPEED`, tagged with a `Fuse` so it despawns after a fixed
+/// lifetime even if it never hits anything.
+fn spawn_bullet<'a, 'b>(
+    commands: &'a mut Commands<'b>,
+    material: Handle<ColorMaterial>,
+    transform: Transform,
+    direction: Vec2,
+) -> EntityCommands<'a, 'b> {
+    let mut entity = spawn_rigid_body_rectangle(
+        commands.spawn(),
+        material,
+        transform,
+        Vec2::new(8.0, 8.0),
+    );
+    entity
+        .insert(Bullet)
+        .insert(Fuse(Timer::from_seconds(BULLET_LIFETIME_SECS, false)))
+        .insert(Damage(BULLET_DAMAGE))
+        .insert(RigidBodyVelocity {
+            linvel: [direction.x * BULLET_SPEED, direction.y * BULLET_SPEED].into(),
+            ..Default::default()
+        });
+
+    entity
+}
+
+/// Runs inside the GGRS advance-frame stage only: applies each player's
+/// decoded input to their `RigidBodyVelocity`. No system outside the
+/// rollback schedule may touch rollback state, or clients will desync.
+fn rollback_move_players(
+    inputs: Res<Vec<(BoxInput, ggrs::InputStatus)>>,
+    mut query: Query<(&Player, &mut RigidBodyVelocity, &mut Facing)>,
 ) {
-    if let Ok((_, mut vel)) = query.single_mut() {
+    const SPEED: f32 = 200.0;
+
+    for (player, mut vel, mut facing) in query.iter_mut() {
+        let (input, _status) = inputs[player.handle];
+
         let mut direction = Vector::zeros();
-        if keyboard_input.pressed(KeyCode::Left) {
+        if input.inp & INPUT_LEFT != 0 {
             direction += -1.0f32 * Vector::x();
         }
-
-        if keyboard_input.pressed(KeyCode::Right) {
+        if input.inp & INPUT_RIGHT != 0 {
             direction += 1.0f32 * Vector::x();
         }
-
-        if keyboard_input.pressed(KeyCode::Down) {
+        if input.inp & INPUT_DOWN != 0 {
             direction += -1.0f32 * Vector::y();
         }
-
-        if keyboard_input.pressed(KeyCode::Up) {
+        if input.inp & INPUT_UP != 0 {
             direction += 1.0f32 * Vector::y();
         }
 
-        const SPEED: f32 = 200.0;
         vel.linvel = SPEED * direction;
+
+        if direction.norm_squared() > 0.0 {
+            facing.0 = Vec2::new(direction.x, direction.y).normalize();
+        }
+    }
+}
+
+/// Runs inside the GGRS advance-frame stage, alongside
+/// `rollback_move_players`: spawns a bullet the frame a player's fire
+/// input goes from released to pressed.
+fn rollback_fire_bullets(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    inputs: Res<Vec<(BoxInput, ggrs::InputStatus)>>,
+    mut rollback_ids: ResMut<RollbackIdProvider>,
+    mut query: Query<(&mut Player, &Transform, &Facing)>,
+) {
+    for (mut player, transform, facing) in query.iter_mut() {
+        let (input, _status) = inputs[player.handle];
+
+        let just_fired = input.inp & INPUT_FIRE != 0 && player.prev_inp & INPUT_FIRE == 0;
+        player.prev_inp = input.inp;
+
+        if just_fired {
+            let spawn_at = transform2d(
+                transform.translation.x + facing.0.x * 20.0,
+                transform.translation.y + facing.0.y * 20.0,
+                transform.translation.z,
+                0.0,
+            );
+            let bullet_material = materials.add(Color::rgb(1.0, 1.0, 0.2).into());
+            spawn_bullet(&mut commands, bullet_material, spawn_at, facing.0)
+                .insert(Rollback::new(rollback_ids.next_id()));
+        }
+    }
+}
+
+/// Eases the camera toward the local player's position each frame. Runs
+/// in `PostUpdate`, after Rapier has written the post-step `Transform`
+/// back, so it never lags a frame behind the physics.
+fn camera_follow(
+    time: Res<Time>,
+    follow_speed: Res<CameraFollowSpeed>,
+    player_query: Query<&Transform, With<LocalPlayer>>,
+    mut camera_query: Query<&mut Transform, With<MainCamera>>,
+) {
+    let player_transform = match player_query.single() {
+        Ok(transform) => transform,
+        Err(_) => return,
+    };
+    let target = player_transform.translation;
+
+    for mut camera_transform in camera_query.iter_mut() {
+        let current = camera_transform.translation;
+        let t = (follow_speed.0 * time.delta_seconds()).min(1.0);
+        camera_transform.translation.x = current.x + (target.x - current.x) * t;
+        camera_transform.translation.y = current.y + (target.y - current.y) * t;
+    }
+}
+
+/// Despawns bullets whose `Fuse` has run out. Runs inside the GGRS
+/// advance-frame stage, so it ticks by one fixed simulation step
+/// (`1 / FPS`) per call rather than `Res<Time>`'s wall-clock delta —
+/// using real time here would tick a different amount on every
+/// resimulation of the same virtual frame, and `Fuse` would never agree
+/// between peers.
+fn tick_fuses(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut Fuse), With<Bullet>>,
+) {
+    let step = Duration::from_secs_f32(1.0 / FPS);
+
+    for (entity, mut fuse) in query.iter_mut() {
+        if fuse.0.tick(step).finished() {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Runs inside the GGRS advance-frame stage: listens for Rapier
+/// `CollisionEvent::Started`, applies `Damage` to whatever `Health` it
+/// hits (despawning the victim at zero health), then despawns any bullet
+/// involved in the collision. Both have to happen in the same system, in
+/// that order: `Commands` are only flushed at stage boundaries, so a
+/// bullet despawned in an earlier stage would already be gone by the
+/// time a later system tried to read its `Damage`. It must also live in
+/// the rollback schedule rather than a wall-clock stage: the Rapier step
+/// that produces these events now runs once per resimulated frame, so
+/// applying damage anywhere else would double-apply it on misprediction
+/// and `Health` would never be rolled back to match.
+fn collision_event_system(
+    mut commands: Commands,
+    mut collision_events: EventReader<CollisionEvent>,
+    damaging: Query<&Damage>,
+    mut healthy: Query<&mut Health>,
+    bullets: Query<&Bullet>,
+) {
+    let mut despawned = HashSet::new();
+
+    for event in collision_events.iter() {
+        if let CollisionEvent::Started(entity1, entity2, _flags) = event {
+            for &(attacker, victim) in &[(*entity1, *entity2), (*entity2, *entity1)] {
+                let damage = match damaging.get(attacker) {
+                    Ok(damage) => damage.0,
+                    Err(_) => continue,
+                };
+
+                let mut health = match healthy.get_mut(victim) {
+                    Ok(health) => health,
+                    Err(_) => continue,
+                };
+
+                health.current = (health.current - damage).max(0.0);
+                if health.current <= 0.0 && despawned.insert(victim) {
+                    commands.entity(victim).despawn_recursive();
+                }
+            }
+
+            for &entity in &[*entity1, *entity2] {
+                if bullets.get(entity).is_ok() && despawned.insert(entity) {
+                    commands.entity(entity).despawn_recursive();
+                }
+            }
+        }
     }
 }